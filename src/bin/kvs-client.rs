@@ -0,0 +1,107 @@
+use std::net::{SocketAddr, TcpStream};
+use std::process::exit;
+
+use clap::{App, Arg, SubCommand};
+
+use kvs::protocol::{read_frame, write_frame, GetResponse, RemoveResponse, Request, SetResponse};
+use kvs::Result;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> Result<()> {
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("Sets the server address to connect to")
+                .takes_value(true)
+                .default_value(DEFAULT_ADDR)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set the value of a string key to a string")
+                .args(&[
+                    Arg::with_name("KEY").required(true),
+                    Arg::with_name("VALUE").required(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get the string value of a given string key")
+                .arg(Arg::with_name("KEY").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a given key")
+                .arg(Arg::with_name("KEY").required(true)),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = matches
+        .value_of("addr")
+        .unwrap()
+        .parse()
+        .expect("Invalid --addr");
+
+    match matches.subcommand() {
+        ("set", Some(args)) => {
+            let request = Request::Set {
+                key: args.value_of("KEY").unwrap().to_string(),
+                value: args.value_of("VALUE").unwrap().to_string(),
+            };
+            let mut stream = TcpStream::connect(addr)?;
+            write_frame(&mut stream, &request)?;
+            match read_frame(&mut stream)? {
+                SetResponse::Ok(()) => exit(0),
+                SetResponse::Err(msg) => {
+                    eprintln!("{}", msg);
+                    exit(1);
+                }
+            }
+        }
+        ("get", Some(args)) => {
+            let request = Request::Get {
+                key: args.value_of("KEY").unwrap().to_string(),
+            };
+            let mut stream = TcpStream::connect(addr)?;
+            write_frame(&mut stream, &request)?;
+            match read_frame(&mut stream)? {
+                GetResponse::Ok(Some(value)) => {
+                    println!("{}", value);
+                    exit(0);
+                }
+                GetResponse::Ok(None) => {
+                    println!("Key not found");
+                    exit(0);
+                }
+                GetResponse::Err(msg) => {
+                    eprintln!("{}", msg);
+                    exit(1);
+                }
+            }
+        }
+        ("rm", Some(args)) => {
+            let request = Request::Remove {
+                key: args.value_of("KEY").unwrap().to_string(),
+            };
+            let mut stream = TcpStream::connect(addr)?;
+            write_frame(&mut stream, &request)?;
+            match read_frame(&mut stream)? {
+                RemoveResponse::Ok(()) => exit(0),
+                RemoveResponse::Err(msg) => {
+                    println!("{}", msg);
+                    exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("Invalid argument");
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::env::current_dir;
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::exit;
+
+use clap::{App, Arg};
+
+use kvs::protocol::{read_frame, write_frame, GetResponse, RemoveResponse, Request, SetResponse};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
+const ENGINE_MARKER_FILE: &str = "engine";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("Sets the listening address")
+                .takes_value(true)
+                .default_value(DEFAULT_ADDR),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Sets the storage engine")
+                .takes_value(true)
+                .possible_values(&["kvs", "sled"])
+                .default_value(DEFAULT_ENGINE),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = matches
+        .value_of("addr")
+        .unwrap()
+        .parse()
+        .expect("Invalid --addr");
+    let engine_name = matches.value_of("engine").unwrap();
+
+    eprintln!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    eprintln!("storage engine: {}", engine_name);
+
+    let dir = current_dir()?;
+    ensure_engine(&dir, engine_name)?;
+
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("listening on {}", addr);
+    match engine_name {
+        "kvs" => run_with_engine(KvStore::open(dir)?, pool, listener),
+        "sled" => run_with_engine(SledKvsEngine::open(dir)?, pool, listener),
+        _ => unreachable!("clap restricts --engine to known values"),
+    }
+}
+
+/// Persists which engine a (possibly fresh) data directory was opened
+/// with, and refuses to reopen it with a different one.
+fn ensure_engine(dir: &Path, engine_name: &str) -> Result<()> {
+    let marker = dir.join(ENGINE_MARKER_FILE);
+    if !marker.is_file() {
+        fs::write(&marker, engine_name)?;
+        return Ok(());
+    }
+    let prev_engine = fs::read_to_string(&marker)?;
+    if prev_engine != engine_name {
+        return Err(KvsError::EngineMismatch(
+            prev_engine,
+            engine_name.to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!(
+            "kvs-server-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            nanos
+        ))
+    }
+
+    #[test]
+    fn ensure_engine_accepts_reopening_with_the_same_engine() {
+        let dir = unique_temp_dir("same-engine");
+        fs::create_dir_all(&dir).unwrap();
+
+        ensure_engine(&dir, "kvs").unwrap();
+        ensure_engine(&dir, "kvs").unwrap();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ensure_engine_rejects_reopening_with_a_different_engine() {
+        let dir = unique_temp_dir("different-engine");
+        fs::create_dir_all(&dir).unwrap();
+
+        ensure_engine(&dir, "kvs").unwrap();
+        let err = ensure_engine(&dir, "sled").unwrap_err();
+        assert!(
+            matches!(err, KvsError::EngineMismatch(prev, new) if prev == "kvs" && new == "sled")
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(
+    engine: E,
+    pool: SharedQueueThreadPool,
+    listener: TcpListener,
+) -> Result<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let engine = engine.clone();
+                pool.spawn(move || {
+                    if let Err(e) = serve(&engine, stream) {
+                        eprintln!("error serving client: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Serves a single request read from `stream`, writing the response back
+/// on the same connection.
+fn serve(engine: &impl KvsEngine, mut stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let request: Request = read_frame(&mut stream)?;
+    match request {
+        Request::Set { key, value } => {
+            let response = match engine.set(key, value) {
+                Ok(()) => SetResponse::Ok(()),
+                Err(e) => SetResponse::Err(e.to_string()),
+            };
+            write_frame(&mut stream, &response)?;
+        }
+        Request::Get { key } => {
+            let response = match engine.get(key) {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(e.to_string()),
+            };
+            write_frame(&mut stream, &response)?;
+        }
+        Request::Remove { key } => {
+            let response = match engine.remove(key) {
+                Ok(()) => RemoveResponse::Ok(()),
+                Err(e) => RemoveResponse::Err(e.to_string()),
+            };
+            write_frame(&mut stream, &response)?;
+        }
+    }
+    eprintln!("served request from {}", peer_addr);
+    Ok(())
+}
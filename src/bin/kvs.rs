@@ -6,6 +6,15 @@ use clap::{App, Arg, SubCommand};
 use kvs::{KvStore, KvsError, Result};
 
 fn main() -> Result<()> {
+    // Run the whole command in a helper and only call `exit` afterwards,
+    // once every `KvStore` it opened has already gone out of scope and run
+    // its normal `Drop` (which is where the index hint gets saved) --
+    // `exit` terminates the process immediately and skips destructors.
+    let code = run()?;
+    exit(code);
+}
+
+fn run() -> Result<i32> {
     let storage_dir = env::current_dir()?;
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -33,36 +42,36 @@ fn main() -> Result<()> {
 
     match matches.subcommand() {
         ("set", Some(args)) => {
-            let mut kvs = KvStore::open(storage_dir)?;
+            let kvs = KvStore::open(storage_dir)?;
             kvs.set(
                 args.value_of("KEY").unwrap().to_string(),
                 args.value_of("VALUE").unwrap().to_string(),
             )?;
-            exit(0);
+            Ok(0)
         }
         ("get", Some(args)) => {
-            let mut kvs = KvStore::open(storage_dir)?;
+            let kvs = KvStore::open(storage_dir)?;
             if let Some(val) = kvs.get(args.value_of("KEY").unwrap().to_string())? {
                 println!("{}", val);
             } else {
                 println!("Key not found");
             }
-            exit(0);
+            Ok(0)
         }
         ("rm", Some(args)) => {
-            let mut kvs = KvStore::open(storage_dir)?;
+            let kvs = KvStore::open(storage_dir)?;
             match kvs.remove(args.value_of("KEY").unwrap().to_string()) {
-                Ok(()) => exit(0),
+                Ok(()) => Ok(0),
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
-                    exit(1);
+                    Ok(1)
                 }
-                Err(e) => return Err(e),
-            };
+                Err(e) => Err(e),
+            }
         }
         _ => {
             eprintln!("Invalid argument");
-            exit(1);
+            Ok(1)
         }
-    };
+    }
 }
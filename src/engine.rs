@@ -0,0 +1,28 @@
+use crate::Result;
+
+/// Trait for a key/value storage engine.
+///
+/// Implementors persist string key/value pairs and expose the same
+/// `set`/`get`/`remove` surface regardless of the underlying storage
+/// format, so a `kvs-server` can be started against whichever engine
+/// was requested on the command line.
+///
+/// Methods take `&self` rather than `&mut self` so a single engine
+/// instance can be cloned and shared across the worker threads of a
+/// `ThreadPool` without an outer lock serializing every request.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key does not exist.
+    fn remove(&self, key: String) -> Result<()>;
+}
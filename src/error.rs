@@ -13,6 +13,25 @@ pub enum KvsError {
     /// or removed does not exist.
     #[error("Key not found")]
     KeyNotFound,
+    /// Error from the embedded `sled` storage engine.
+    #[error("{0}")]
+    Sled(sled::Error),
+    /// A value read back from an alternate storage engine was not valid UTF-8.
+    #[error("{0}")]
+    Utf8(std::string::FromUtf8Error),
+    /// A log record the index points at failed its CRC check when read back,
+    /// indicating the log was corrupted after it was indexed.
+    #[error("log record checksum mismatch")]
+    ChecksumMismatch,
+    /// A wire protocol frame advertised a length longer than
+    /// `protocol::MAX_FRAME_LEN`, so it was rejected before allocating a
+    /// buffer for it.
+    #[error("frame length {0} exceeds the maximum of {1}")]
+    FrameTooLarge(u32, u32),
+    /// `kvs-server` was asked to open a data directory with a different
+    /// storage engine than the one it was first opened with.
+    #[error("data directory was previously opened with engine '{0}', cannot reopen with '{1}'")]
+    EngineMismatch(String, String),
 }
 
 /// Result type for kvs.
@@ -29,3 +48,15 @@ impl From<serde_json::Error> for KvsError {
         KvsError::Deserialization(err)
     }
 }
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> Self {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        KvsError::Utf8(err)
+    }
+}
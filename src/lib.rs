@@ -2,9 +2,15 @@
 //! A simple key/value store.
 
 // THe modules are private.
-mod kv;
+mod engine;
 mod error;
+mod kv;
+pub mod protocol;
+mod sled_engine;
+pub mod thread_pool;
 
 // Use `pub use` to re-export the modules
-pub use kv::KvStore;
+pub use engine::KvsEngine;
 pub use error::{KvsError, Result};
+pub use kv::{KvStore, WriteBatch};
+pub use sled_engine::SledKvsEngine;
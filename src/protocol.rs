@@ -0,0 +1,140 @@
+//! Wire protocol shared by `kvs-client` and `kvs-server`.
+//!
+//! Requests and responses are serde-serialized to JSON and framed with a
+//! 4-byte big-endian length prefix, so a reader always knows exactly how
+//! many bytes to pull off the socket before handing them to `serde_json`.
+
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// The largest frame `read_frame` will allocate a buffer for. Requests and
+/// responses are a handful of short strings, so this is generous while
+/// still keeping a single misbehaving connection from driving `kvs-server`
+/// to allocate up to 4 GiB off an untrusted length prefix.
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+
+/// A request sent from a `kvs-client` to a `kvs-server`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Set the value of a string key to a string.
+    Set {
+        /// The key to set.
+        key: String,
+        /// The value to associate with `key`.
+        value: String,
+    },
+    /// Get the string value of a given string key.
+    Get {
+        /// The key to look up.
+        key: String,
+    },
+    /// Remove a given key.
+    Remove {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+/// The response to a `Request::Get`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetResponse {
+    /// The lookup succeeded; carries the value if the key was present.
+    Ok(Option<String>),
+    /// The lookup failed; carries a human-readable error message.
+    Err(String),
+}
+
+/// The response to a `Request::Set`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetResponse {
+    /// The write succeeded.
+    Ok(()),
+    /// The write failed; carries a human-readable error message.
+    Err(String),
+}
+
+/// The response to a `Request::Remove`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemoveResponse {
+    /// The removal succeeded.
+    Ok(()),
+    /// The removal failed; carries a human-readable error message, e.g.
+    /// when the key was not found.
+    Err(String),
+}
+
+/// Writes `value` to `writer` as a length-prefixed JSON frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = serde_json::to_vec(value)?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a single length-prefixed JSON frame from `reader` and deserializes it.
+///
+/// Returns `KvsError::FrameTooLarge` without allocating a buffer if the
+/// advertised length exceeds `MAX_FRAME_LEN`, since the prefix comes from
+/// whoever is on the other end of the connection and is not otherwise
+/// bounded.
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(reader: &mut R) -> Result<T> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::FrameTooLarge(len, MAX_FRAME_LEN));
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn write_frame_then_read_frame_roundtrips() {
+        let request = Request::Set {
+            key: "k".to_owned(),
+            value: "v".to_owned(),
+        };
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &request).unwrap();
+
+        let read_back: Request = read_frame(&mut Cursor::new(buf)).unwrap();
+        match read_back {
+            Request::Set { key, value } => {
+                assert_eq!(key, "k");
+                assert_eq!(value, "v");
+            }
+            other => panic!("expected Request::Set, got {:?}", other),
+        }
+    }
+
+    /// A length prefix larger than `MAX_FRAME_LEN` must be rejected before
+    /// a buffer is allocated for it, rather than trusted as-is.
+    #[test]
+    fn read_frame_rejects_a_length_above_the_max() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+
+        match read_frame::<_, Request>(&mut Cursor::new(buf)) {
+            Err(KvsError::FrameTooLarge(len, max)) => {
+                assert_eq!(len, MAX_FRAME_LEN + 1);
+                assert_eq!(max, MAX_FRAME_LEN);
+            }
+            other => panic!(
+                "expected FrameTooLarge, got {:?}",
+                other.map(|_: Request| ())
+            ),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+
+use sled::Db;
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// This lets a `kvs-server` be benchmarked against `KvStore`'s own bitcask
+/// log by selecting `--engine sled` instead of `--engine kvs`.
+#[derive(Clone)]
+pub struct SledKvsEngine(Db);
+
+impl SledKvsEngine {
+    /// Opens a `sled`-backed engine rooted at `path`, creating the
+    /// directory if it does not already exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let db = sled::open(path.into())?;
+        Ok(SledKvsEngine(db))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.insert(key, value.into_bytes())?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0
+            .get(key)?
+            .map(|value| String::from_utf8(value.to_vec()).map_err(KvsError::from))
+            .transpose()
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let removed = self.0.remove(key)?;
+        self.0.flush()?;
+        removed.map(|_| ()).ok_or(KvsError::KeyNotFound)
+    }
+}
@@ -0,0 +1,139 @@
+//! Thread pool abstractions used to run request handlers concurrently.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::Result;
+
+/// A pool of threads that can be handed jobs to run.
+pub trait ThreadPool {
+    /// Creates a new thread pool with `threads` worker threads.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Spawns a job onto the pool. The job runs on one of the pool's
+    /// worker threads; a job that panics must not take the pool down
+    /// with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// A `ThreadPool` that spawns a brand new OS thread for every job.
+///
+/// This does no pooling at all; it exists as the simplest possible
+/// `ThreadPool` implementation and as a baseline to compare the other
+/// implementations against.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` backed by a fixed set of worker threads pulling jobs off
+/// a shared queue.
+///
+/// If a job panics, the worker thread that ran it is not lost: a guard
+/// detects the unwind and spawns a replacement worker listening on the
+/// same queue, so the pool's capacity never shrinks.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..threads {
+            spawn_worker(Arc::clone(&receiver));
+        }
+
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the thread pool's worker threads have all exited");
+    }
+}
+
+/// Spawns a single worker thread that pulls jobs from `receiver` until the
+/// pool is dropped. Wrapped in a `Sentinel` so a panicking job respawns the
+/// worker instead of permanently shrinking the pool.
+fn spawn_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    thread::spawn(move || {
+        let sentinel = Sentinel(Some(Arc::clone(&receiver)));
+        loop {
+            let job = {
+                let receiver = receiver.lock().unwrap();
+                receiver.recv()
+            };
+            match job {
+                Ok(job) => job(),
+                // The sending half was dropped; the pool is shutting down.
+                Err(_) => break,
+            }
+        }
+        sentinel.cancel();
+    });
+}
+
+/// Respawns a replacement worker thread when dropped during a panic.
+struct Sentinel(Option<Arc<Mutex<Receiver<Job>>>>);
+
+impl Sentinel {
+    fn cancel(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.0.take() {
+            if thread::panicking() {
+                spawn_worker(receiver);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A job that panics must not take its worker thread down with it: a
+    /// single-worker pool should still run a job spawned afterward.
+    #[test]
+    fn shared_queue_thread_pool_survives_a_panicking_job() {
+        let pool = SharedQueueThreadPool::new(1).unwrap();
+
+        pool.spawn(|| panic!("a job panicking should not take its worker down"));
+
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(move || tx.send(()).unwrap());
+
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("pool should still be running jobs after one panicked");
+    }
+}